@@ -2,34 +2,381 @@
 // Copyright 2024-2025 Rusty Conover <rusty@query.farm>
 // Licensed under the MIT License
 
+use std::cell::RefCell;
 use std::ffi::{c_char, CString};
 use std::ptr;
+use std::rc::Rc;
 use std::slice;
 use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use rhai::{packages::Package, Dynamic, Engine, Scope, AST};
+use rhai::{
+    packages::Package, Array, Dynamic, Engine, EvalAltResult, Map, NativeCallContext,
+    OptimizationLevel, Scope, AST,
+};
 //use rhai_chrono::ChronoPackage;
 use rhai_fs::FilesystemPackage;
 use rhai_rand::RandomPackage;
 use rhai_sci::SciPackage;
 use rhai_url::UrlPackage;
 
+/// Resource governors applied to an `Engine` before it compiles or evaluates a script.
+///
+/// Every field is a ceiling; `0` leaves that governor unset. `timeout_ms` is
+/// wall-clock, enforced via `Engine::on_progress`.
+#[repr(C)]
+pub struct ResourceLimits {
+    pub max_operations: u64,
+    pub max_expr_depth: usize,
+    pub max_array_size: usize,
+    pub max_string_size: usize,
+    pub max_map_size: usize,
+    pub timeout_ms: u64,
+}
+
+/// Apply the optional resource governors to `engine`.
+///
+/// `limits` may be null, in which case no governors are installed. A `0` value for
+/// any individual field is likewise treated as "leave this governor unset".
+fn apply_resource_limits(engine: &mut Engine, limits: *const ResourceLimits) {
+    if limits.is_null() {
+        return;
+    }
+
+    let limits = unsafe { &*limits };
+
+    if limits.max_operations > 0 {
+        engine.set_max_operations(limits.max_operations);
+    }
+    if limits.max_expr_depth > 0 {
+        engine.set_max_expr_depths(limits.max_expr_depth, limits.max_expr_depth);
+    }
+    if limits.max_array_size > 0 {
+        engine.set_max_array_size(limits.max_array_size);
+    }
+    if limits.max_string_size > 0 {
+        engine.set_max_string_size(limits.max_string_size);
+    }
+    if limits.max_map_size > 0 {
+        engine.set_max_map_size(limits.max_map_size);
+    }
+}
+
+/// Read the `timeout_ms` governor out of an optional `ResourceLimits`, or `0` if
+/// `limits` is null.
+fn resource_timeout_ms(limits: *const ResourceLimits) -> u64 {
+    if limits.is_null() {
+        0
+    } else {
+        unsafe { (*limits).timeout_ms }
+    }
+}
+
+/// (Re)install the wall-clock deadline for the next evaluation on `engine`.
+///
+/// Must be called fresh before each eval, not once at compile time, since an engine
+/// is reused across many calls.
+fn install_timeout(engine: &mut Engine, timeout_ms: u64) {
+    if timeout_ms == 0 {
+        return;
+    }
+
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+    let ops_seen = Arc::new(AtomicU64::new(0));
+
+    engine.on_progress(move |count| {
+        ops_seen.store(count, Ordering::Relaxed);
+
+        if Instant::now() >= deadline {
+            Some(Dynamic::from(format!(
+                "script exceeded {} ms time limit after {} operations",
+                timeout_ms,
+                ops_seen.load(Ordering::Relaxed)
+            )))
+        } else {
+            None
+        }
+    });
+}
+
+/// Render an `EvalAltResult` for an FFI caller.
+///
+/// `EvalAltResult`'s `Display` impl drops the diagnostic token passed to
+/// `ErrorTerminated` (e.g. the time-limit message from `install_timeout`), so
+/// pull it out explicitly rather than losing it behind "Script terminated".
+fn format_eval_error(error: &EvalAltResult) -> String {
+    if let EvalAltResult::ErrorTerminated(token, _) = error {
+        token.to_string()
+    } else {
+        error.to_string()
+    }
+}
+
+/// Capability flag selecting the privileged engine profile.
+///
+/// The default (`0`) profile is locked down for untrusted SQL expressions: no
+/// filesystem/URL access, no dynamic `eval`, no module loading. This flag lifts
+/// those restrictions for trusted expression sources.
+pub const CAPABILITY_PRIVILEGED: u32 = 1 << 0;
+
+fn is_privileged(capabilities: u32) -> bool {
+    capabilities & CAPABILITY_PRIVILEGED != 0
+}
+
+/// Register packages on `engine` according to `capabilities`, locking down dynamic
+/// `eval` and module loading unless the privileged capability is set.
+fn configure_capabilities(engine: &mut Engine, capabilities: u32) {
+    engine.register_global_module(RandomPackage::new().as_shared_module());
+    engine.register_global_module(SciPackage::new().as_shared_module());
+
+    if is_privileged(capabilities) {
+        engine.register_global_module(FilesystemPackage::new().as_shared_module());
+        engine.register_global_module(UrlPackage::new().as_shared_module());
+    } else {
+        engine.disable_symbol("eval");
+        engine.set_max_modules(0);
+    }
+}
+
+/// Wire up `state()`/`set_state(value)` script functions backed by a persistent
+/// `Dynamic` carried across evaluations of the same `CompiledAst`.
+///
+/// Returns the shared handle so the caller can stash it on the `CompiledAst` and
+/// clear it from `reset_ast_state`.
+fn register_stateful_functions(engine: &mut Engine) -> Rc<RefCell<Dynamic>> {
+    let state = Rc::new(RefCell::new(Dynamic::UNIT));
+
+    engine.set_default_tag(Dynamic::from(state.clone()));
+
+    engine.register_fn("state", |context: NativeCallContext| -> Dynamic {
+        context
+            .tag()
+            .and_then(Dynamic::downcast_ref::<Rc<RefCell<Dynamic>>>)
+            .map(|state| state.borrow().clone())
+            .unwrap_or(Dynamic::UNIT)
+    });
+
+    engine.register_fn("set_state", |context: NativeCallContext, value: Dynamic| {
+        if let Some(state) = context
+            .tag()
+            .and_then(Dynamic::downcast_ref::<Rc<RefCell<Dynamic>>>)
+        {
+            *state.borrow_mut() = value;
+        }
+    });
+
+    state
+}
+
 #[repr(C)]
 pub enum ResultCString {
     Ok(*mut c_char),
     Err(*mut c_char),
 }
 
-macro_rules! make_str {
-    ( $s : expr , $len : expr ) => {
-        unsafe { str::from_utf8_unchecked(slice::from_raw_parts($s as *const u8, $len)) }
-    };
+/// Build a `CString` from a message that may not be a valid C string.
+///
+/// `CString::new` fails on embedded NUL bytes; rather than `.unwrap()` (which would
+/// panic and unwind across the FFI boundary), strip any embedded NULs so an error or
+/// result message can never bring down the host process.
+fn safe_cstring(message: String) -> CString {
+    CString::new(message).unwrap_or_else(|err| {
+        let mut bytes = err.into_vec();
+        bytes.retain(|&b| b != 0);
+        CString::new(bytes).unwrap_or_default()
+    })
 }
 
-macro_rules! make_str2 {
-    ( $s : expr , $len : expr ) => {
-        str::from_utf8_unchecked(slice::from_raw_parts($s as *const u8, $len))
-    };
+fn result_cstring_ok(message: String) -> ResultCString {
+    ResultCString::Ok(safe_cstring(message).into_raw())
+}
+
+fn result_cstring_err(message: String) -> ResultCString {
+    ResultCString::Err(safe_cstring(message).into_raw())
+}
+
+fn result_compiled_ast_err(message: String) -> *mut ResultCompiledAst {
+    Box::into_raw(Box::new(ResultCompiledAst::Err(
+        safe_cstring(message).into_raw(),
+    )))
+}
+
+/// View a `(pointer, length)` FFI argument as a `&str`.
+///
+/// # Safety
+///
+/// The caller must ensure `ptr` is valid for reads of `len` bytes for the lifetime
+/// `'a` that the returned `&str` is used within.
+unsafe fn bytes_to_str<'a>(ptr: *const c_char, len: usize) -> Result<&'a str, str::Utf8Error> {
+    let bytes = slice::from_raw_parts(ptr as *const u8, len);
+    str::from_utf8(bytes)
+}
+
+/// The largest integer an IEEE-754 `f64` can represent exactly (2^53).
+///
+/// Integers past this are emitted as quoted JSON strings so f64-based JSON
+/// consumers don't silently lose precision.
+const JSON_SAFE_INTEGER_LIMIT: u64 = 9_007_199_254_740_992;
+
+fn is_integer_literal(s: &str) -> bool {
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// `u64::MAX` is 20 digits; `serde_json` silently collapses any unquoted integer
+/// literal above it into a lossy `f64` before it ever reaches
+/// `json_value_to_dynamic`, so literals of 20 or more digits are caught in the raw
+/// text instead.
+const MAX_SAFE_UNQUOTED_INTEGER_DIGITS: usize = 19;
+
+/// Reject unquoted JSON integer literals `serde_json` would silently turn into a
+/// lossy `f64`.
+///
+/// DuckDB HUGEINT values must be quoted as JSON strings to round-trip exactly (see
+/// `json_value_to_dynamic`); this catches the unquoted case before that precision
+/// loss happens rather than after.
+fn reject_oversized_integer_literals(json: &str) -> Result<(), String> {
+    let bytes = json.as_bytes();
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let b = bytes[i];
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if b == b'\\' {
+                escaped = true;
+            } else if b == b'"' {
+                in_string = false;
+            }
+            i += 1;
+            continue;
+        }
+        if b == b'"' {
+            in_string = true;
+            i += 1;
+            continue;
+        }
+        if b.is_ascii_digit() {
+            let start = i;
+            while i < bytes.len() && bytes[i].is_ascii_digit() {
+                i += 1;
+            }
+            let digits_end = i;
+            // A fraction or exponent makes this a float literal, not an integer one.
+            while i < bytes.len()
+                && matches!(bytes[i], b'0'..=b'9' | b'.' | b'e' | b'E' | b'+' | b'-')
+            {
+                i += 1;
+            }
+            if i == digits_end && digits_end - start > MAX_SAFE_UNQUOTED_INTEGER_DIGITS {
+                return Err(format!(
+                    "integer literal '{}' must be quoted as a JSON string to avoid precision loss",
+                    &json[start..digits_end]
+                ));
+            }
+            continue;
+        }
+        i += 1;
+    }
+
+    Ok(())
+}
+
+/// Convert a parsed JSON value into a Rhai `Dynamic`.
+///
+/// Integers (quoted or not) round-trip exactly as long as they fit in Rhai's `i64`
+/// `INT` type; one outside that range is rejected with an `Err` rather than silently
+/// degraded through a lossy `f64` or turned into a string. Callers must run
+/// `reject_oversized_integer_literals` on the raw JSON text first to catch unquoted
+/// literals `serde_json` itself would otherwise degrade before this function sees
+/// them.
+fn json_value_to_dynamic(value: serde_json::Value) -> Result<Dynamic, String> {
+    match value {
+        serde_json::Value::Null => Ok(Dynamic::UNIT),
+        serde_json::Value::Bool(b) => Ok(Dynamic::from(b)),
+        serde_json::Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                Ok(Dynamic::from(i))
+            } else if n.is_u64() {
+                Err(format!(
+                    "integer {} is outside the 64-bit range Rhai's INT type supports",
+                    n
+                ))
+            } else {
+                Ok(n.as_f64().map(Dynamic::from).unwrap_or(Dynamic::UNIT))
+            }
+        }
+        serde_json::Value::String(s) => {
+            if is_integer_literal(&s) {
+                return match s.parse::<i64>() {
+                    Ok(i) => Ok(Dynamic::from(i)),
+                    Err(_) => Err(format!(
+                        "integer literal '{}' is outside the 64-bit range Rhai's INT type supports",
+                        s
+                    )),
+                };
+            }
+            Ok(Dynamic::from(s))
+        }
+        serde_json::Value::Array(items) => {
+            let array: Array = items
+                .into_iter()
+                .map(json_value_to_dynamic)
+                .collect::<Result<_, _>>()?;
+            Ok(Dynamic::from(array))
+        }
+        serde_json::Value::Object(fields) => {
+            let map: Map = fields
+                .into_iter()
+                .map(|(key, value)| Ok((key.into(), json_value_to_dynamic(value)?)))
+                .collect::<Result<_, String>>()?;
+            Ok(Dynamic::from(map))
+        }
+    }
+}
+
+/// Convert a Rhai `Dynamic` back into a JSON value.
+///
+/// The inverse of `json_value_to_dynamic`: integers past `JSON_SAFE_INTEGER_LIMIT`
+/// are emitted as quoted strings instead of numbers.
+fn dynamic_to_json_value(value: &Dynamic) -> serde_json::Value {
+    if value.is_unit() {
+        return serde_json::Value::Null;
+    }
+    if let Ok(b) = value.as_bool() {
+        return serde_json::Value::Bool(b);
+    }
+    if let Ok(i) = value.as_int() {
+        return if i.unsigned_abs() > JSON_SAFE_INTEGER_LIMIT {
+            serde_json::Value::String(i.to_string())
+        } else {
+            serde_json::Value::Number(i.into())
+        };
+    }
+    if let Ok(f) = value.as_float() {
+        return serde_json::json!(f);
+    }
+    if let Ok(s) = value.clone().into_immutable_string() {
+        return serde_json::Value::String(s.to_string());
+    }
+    if let Some(array) = value.clone().try_cast::<Array>() {
+        return serde_json::Value::Array(array.iter().map(dynamic_to_json_value).collect());
+    }
+    if let Some(map) = value.clone().try_cast::<Map>() {
+        let fields = map
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), dynamic_to_json_value(&value)))
+            .collect();
+        return serde_json::Value::Object(fields);
+    }
+
+    // Fall back to Dynamic's own serde support for any other type.
+    serde_json::to_value(value).unwrap_or(serde_json::Value::Null)
 }
 
 /// A compiled AST
@@ -39,6 +386,15 @@ macro_rules! make_str2 {
 pub struct CompiledAst {
     engine: Box<Engine>,
     ast: Box<AST>,
+    /// Persistent state shared with the `state()`/`set_state(value)` script
+    /// functions; carried across `eval_ast_stateful` calls until `reset_ast_state`
+    /// clears it. See `register_stateful_functions`.
+    state: Rc<RefCell<Dynamic>>,
+    /// Reusable scope for `eval_ast_stateful`, rewound (not recreated) between rows.
+    scope: Scope<'static>,
+    /// `timeout_ms` from the `ResourceLimits` this AST was compiled with, re-applied
+    /// to `engine` at the start of every eval call. See `install_timeout`.
+    timeout_ms: u64,
 }
 
 /// A result of a compiled AST
@@ -53,38 +409,133 @@ pub enum ResultCompiledAst {
 }
 
 /// Compile an expression into an AST
+///
+/// `limits` is an optional pointer to a `ResourceLimits`; pass null for no limits.
+/// `capabilities` is a `CAPABILITY_*` bitmask (see `configure_capabilities`).
 #[no_mangle]
 pub extern "C" fn compile_ast(
     expression: *const c_char,
     expression_len: usize,
+    limits: *const ResourceLimits,
+    capabilities: u32,
 ) -> *mut ResultCompiledAst {
-    let expr_str = make_str!(expression, expression_len);
+    let expr_str = match unsafe { bytes_to_str(expression, expression_len) } {
+        Ok(expr_str) => expr_str,
+        Err(error) => {
+            return result_compiled_ast_err(format!("expression was not valid UTF-8: {}", error))
+        }
+    };
+
     let mut engine = Engine::new();
 
-    engine.register_global_module(RandomPackage::new().as_shared_module());
-    engine.register_global_module(FilesystemPackage::new().as_shared_module());
-    engine.register_global_module(UrlPackage::new().as_shared_module());
-    engine.register_global_module(SciPackage::new().as_shared_module());
+    configure_capabilities(&mut engine, capabilities);
     //    engine.register_global_module(ChronoPackage::new().as_shared_module());
 
-    let ast = engine.compile(expr_str);
+    engine.set_optimization_level(OptimizationLevel::None);
+    apply_resource_limits(&mut engine, limits);
+    let state = register_stateful_functions(&mut engine);
 
-    match ast {
+    match engine.compile(expr_str) {
         Ok(ast) => {
             let compiled = Box::new(CompiledAst {
                 engine: Box::new(engine),
                 ast: Box::new(ast),
+                state,
+                scope: Scope::new(),
+                timeout_ms: resource_timeout_ms(limits),
             });
 
-            let result = Box::new(ResultCompiledAst::Ok(Box::into_raw(compiled)));
-            Box::into_raw(result)
+            Box::into_raw(Box::new(ResultCompiledAst::Ok(Box::into_raw(compiled))))
         }
+        Err(error) => result_compiled_ast_err(format!("{}", error)),
+    }
+}
+
+/// Compile an expression into an AST, folding a set of named constants into it
+///
+/// `constants_json` is a JSON object pushed into the compile-time `Scope` before the
+/// engine optimizes at `OptimizationLevel::Full`. Callers that need to re-bind names
+/// per row should use `compile_ast` instead. `limits`/`capabilities` as in
+/// `compile_ast`.
+#[no_mangle]
+pub extern "C" fn compile_ast_with_constants(
+    expression: *const c_char,
+    expression_len: usize,
+    constants_json: *const c_char,
+    constants_len: usize,
+    limits: *const ResourceLimits,
+    capabilities: u32,
+) -> *mut ResultCompiledAst {
+    let expr_str = match unsafe { bytes_to_str(expression, expression_len) } {
+        Ok(expr_str) => expr_str,
         Err(error) => {
-            let formatted_error = format!("{}", error);
-            let error_str = CString::new(formatted_error).unwrap();
-            let result = Box::new(ResultCompiledAst::Err(error_str.into_raw()));
-            Box::into_raw(result)
+            return result_compiled_ast_err(format!("expression was not valid UTF-8: {}", error))
+        }
+    };
+
+    let mut engine = Engine::new();
+
+    configure_capabilities(&mut engine, capabilities);
+    //    engine.register_global_module(ChronoPackage::new().as_shared_module());
+
+    engine.set_optimization_level(OptimizationLevel::Full);
+    apply_resource_limits(&mut engine, limits);
+
+    let mut scope = Scope::new();
+
+    if constants_len != 0 {
+        let constants_str = match unsafe { bytes_to_str(constants_json, constants_len) } {
+            Ok(constants_str) => constants_str,
+            Err(error) => {
+                return result_compiled_ast_err(format!(
+                    "constants JSON was not valid UTF-8: {}",
+                    error
+                ))
+            }
+        };
+
+        if let Err(error) = reject_oversized_integer_literals(constants_str) {
+            return result_compiled_ast_err(format!("constants JSON: {}", error));
         }
+
+        let constants: serde_json::Map<String, serde_json::Value> =
+            match serde_json::from_str(constants_str) {
+                Ok(constants) => constants,
+                Err(error) => {
+                    return result_compiled_ast_err(format!(
+                        "constants JSON was not well formed: {}",
+                        error
+                    ))
+                }
+            };
+
+        for (name, value) in constants {
+            match json_value_to_dynamic(value) {
+                Ok(value) => {
+                    scope.push_constant(name, value);
+                }
+                Err(error) => {
+                    return result_compiled_ast_err(format!("constant '{}': {}", name, error))
+                }
+            }
+        }
+    }
+
+    let state = register_stateful_functions(&mut engine);
+
+    match engine.compile_with_scope(&scope, expr_str) {
+        Ok(ast) => {
+            let compiled = Box::new(CompiledAst {
+                engine: Box::new(engine),
+                ast: Box::new(ast),
+                state,
+                scope: Scope::new(),
+                timeout_ms: resource_timeout_ms(limits),
+            });
+
+            Box::into_raw(Box::new(ResultCompiledAst::Ok(Box::into_raw(compiled))))
+        }
+        Err(error) => result_compiled_ast_err(format!("{}", error)),
     }
 }
 
@@ -100,8 +551,9 @@ pub extern "C" fn free_ast(ptr: *mut CompiledAst) {
 
 /// Evaluate an AST with a context
 ///
-/// The context is a JSON string that will be deserialized into a `Dynamic` object
-/// and passed to the AST evaluation.
+/// The context is a JSON string deserialized into a `Dynamic` and passed to the
+/// evaluation. Malformed input and native-function panics are reported as
+/// `ResultCString::Err` rather than unwinding across the FFI boundary.
 #[no_mangle]
 pub extern "C" fn eval_ast(
     compiled: *mut CompiledAst,
@@ -111,105 +563,469 @@ pub extern "C" fn eval_ast(
     if compiled.is_null() {
         return ResultCString::Ok(ptr::null_mut());
     }
-    // The json context is optional.
-    unsafe {
-        let result = match context_len == 0 {
-            false => {
-                let context_str = make_str2!(context_json, context_len);
 
-                // Deserialize 'Dynamic' from JSON
-                let context: Dynamic =
-                    serde_json::from_str(&context_str).expect("JSON context was not well formed.");
+    let context = if context_len != 0 {
+        let context_str = match unsafe { bytes_to_str(context_json, context_len) } {
+            Ok(context_str) => context_str,
+            Err(error) => {
+                return result_cstring_err(format!("context JSON was not valid UTF-8: {}", error))
+            }
+        };
+
+        if let Err(error) = reject_oversized_integer_literals(context_str) {
+            return result_cstring_err(format!("JSON context was not well formed: {}", error));
+        }
 
-                // First create the state
-                let mut scope = Scope::new();
+        match serde_json::from_str::<serde_json::Value>(context_str) {
+            Ok(context) => match json_value_to_dynamic(context) {
+                Ok(context) => Some(context),
+                Err(error) => {
+                    return result_cstring_err(format!("JSON context was not well formed: {}", error))
+                }
+            },
+            Err(error) => {
+                return result_cstring_err(format!("JSON context was not well formed: {}", error))
+            }
+        }
+    } else {
+        None
+    };
 
+    let eval_result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let compiled = unsafe { &mut *compiled };
+        install_timeout(&mut compiled.engine, compiled.timeout_ms);
+        match context {
+            Some(context) => {
+                let mut scope = Scope::new();
                 scope.push("context", context);
-
-                // Use the context in an expression
-                (*compiled)
+                compiled
                     .engine
-                    .eval_ast_with_scope::<Dynamic>(&mut scope, &(*compiled).ast)
+                    .eval_ast_with_scope::<Dynamic>(&mut scope, &compiled.ast)
+            }
+            None => compiled.engine.eval_ast::<Dynamic>(&compiled.ast),
+        }
+    }));
+
+    let result = match eval_result {
+        Ok(result) => result,
+        Err(_) => return result_cstring_err("evaluation panicked".to_string()),
+    };
+
+    match result {
+        Ok(output) => match serde_json::to_string(&dynamic_to_json_value(&output)) {
+            Ok(json) => result_cstring_ok(json),
+            Err(error) => {
+                result_cstring_err(format!("failed to serialize result to JSON: {}", error))
+            }
+        },
+        Err(error) => result_cstring_err(format_eval_error(&error)),
+    }
+}
+
+/// Evaluate an AST with a context, carrying `state` across calls
+///
+/// Like `eval_ast`, except the script's `state()`/`set_state(value)` functions
+/// persist a `Dynamic` on `compiled` from one call to the next. Call
+/// `reset_ast_state` between query partitions to clear it.
+#[no_mangle]
+pub extern "C" fn eval_ast_stateful(
+    compiled: *mut CompiledAst,
+    context_json: *const c_char,
+    context_len: usize,
+) -> ResultCString {
+    if compiled.is_null() {
+        return ResultCString::Ok(ptr::null_mut());
+    }
+
+    let context = if context_len != 0 {
+        let context_str = match unsafe { bytes_to_str(context_json, context_len) } {
+            Ok(context_str) => context_str,
+            Err(error) => {
+                return result_cstring_err(format!("context JSON was not valid UTF-8: {}", error))
             }
-            true => (*compiled).engine.eval_ast::<Dynamic>(&(*compiled).ast),
         };
 
-        match result {
-            Ok(output) => {
-                let json = serde_json::to_string(&output)
-                    .expect("Failed to serialize Rhai result to JSON");
-                let value_str = CString::new(json).unwrap();
-                ResultCString::Ok(value_str.into_raw())
+        if let Err(error) = reject_oversized_integer_literals(context_str) {
+            return result_cstring_err(format!("JSON context was not well formed: {}", error));
+        }
+
+        match serde_json::from_str::<serde_json::Value>(context_str) {
+            Ok(context) => match json_value_to_dynamic(context) {
+                Ok(context) => Some(context),
+                Err(error) => {
+                    return result_cstring_err(format!("JSON context was not well formed: {}", error))
+                }
+            },
+            Err(error) => {
+                return result_cstring_err(format!("JSON context was not well formed: {}", error))
+            }
+        }
+    } else {
+        None
+    };
+
+    let eval_result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let compiled = unsafe { &mut *compiled };
+        install_timeout(&mut compiled.engine, compiled.timeout_ms);
+        compiled.scope.rewind(0);
+        if let Some(context) = context {
+            compiled.scope.push("context", context);
+        }
+        compiled
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut compiled.scope, &compiled.ast)
+    }));
+
+    let result = match eval_result {
+        Ok(result) => result,
+        Err(_) => return result_cstring_err("evaluation panicked".to_string()),
+    };
+
+    match result {
+        Ok(output) => match serde_json::to_string(&dynamic_to_json_value(&output)) {
+            Ok(json) => result_cstring_ok(json),
+            Err(error) => {
+                result_cstring_err(format!("failed to serialize result to JSON: {}", error))
             }
+        },
+        Err(error) => result_cstring_err(format_eval_error(&error)),
+    }
+}
+
+/// Clear the persistent `state` carried by `eval_ast_stateful`
+///
+/// Intended for resetting between query partitions, e.g. a `GROUP BY` boundary.
+#[no_mangle]
+pub extern "C" fn reset_ast_state(compiled: *mut CompiledAst) {
+    if compiled.is_null() {
+        return;
+    }
+    let compiled = unsafe { &mut *compiled };
+    *compiled.state.borrow_mut() = Dynamic::UNIT;
+}
+
+/// Call a named function declared in a compiled AST
+///
+/// `args_json` is a JSON array; each element is deserialized into a `Dynamic` and
+/// passed as a positional argument to the `fn` named `fn_name` via `Engine::call_fn`.
+#[no_mangle]
+pub extern "C" fn call_ast_fn(
+    compiled: *mut CompiledAst,
+    fn_name: *const c_char,
+    fn_name_len: usize,
+    args_json: *const c_char,
+    args_len: usize,
+) -> ResultCString {
+    if compiled.is_null() {
+        return ResultCString::Ok(ptr::null_mut());
+    }
+
+    let fn_name = match unsafe { bytes_to_str(fn_name, fn_name_len) } {
+        Ok(fn_name) => fn_name,
+        Err(error) => {
+            return result_cstring_err(format!("function name was not valid UTF-8: {}", error))
+        }
+    };
+
+    let args: Vec<Dynamic> = if args_len != 0 {
+        let args_str = match unsafe { bytes_to_str(args_json, args_len) } {
+            Ok(args_str) => args_str,
             Err(error) => {
-                let formatted_error = format!("{}", error);
-                let error_str = CString::new(formatted_error).unwrap();
-                ResultCString::Err(error_str.into_raw())
+                return result_cstring_err(format!("arguments JSON was not valid UTF-8: {}", error))
             }
+        };
+
+        if let Err(error) = reject_oversized_integer_literals(args_str) {
+            return result_cstring_err(format!("arguments JSON was not well formed: {}", error));
         }
+
+        let args: Vec<serde_json::Value> = match serde_json::from_str(args_str) {
+            Ok(args) => args,
+            Err(error) => {
+                return result_cstring_err(format!(
+                    "arguments JSON was not well formed: {}",
+                    error
+                ))
+            }
+        };
+
+        match args.into_iter().map(json_value_to_dynamic).collect() {
+            Ok(args) => args,
+            Err(error) => {
+                return result_cstring_err(format!("arguments JSON was not well formed: {}", error))
+            }
+        }
+    } else {
+        Vec::new()
+    };
+
+    let call_result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let compiled = unsafe { &mut *compiled };
+        install_timeout(&mut compiled.engine, compiled.timeout_ms);
+        let mut scope = Scope::new();
+        compiled
+            .engine
+            .call_fn::<Dynamic>(&mut scope, &compiled.ast, fn_name, args)
+    }));
+
+    let result = match call_result {
+        Ok(result) => result,
+        Err(_) => return result_cstring_err("function call panicked".to_string()),
+    };
+
+    match result {
+        Ok(output) => match serde_json::to_string(&dynamic_to_json_value(&output)) {
+            Ok(json) => result_cstring_ok(json),
+            Err(error) => {
+                result_cstring_err(format!("failed to serialize result to JSON: {}", error))
+            }
+        },
+        Err(error) => result_cstring_err(format_eval_error(&error)),
     }
 }
 
 /// Evaluate an expression with a optional context
 ///
-/// The context is a JSON string that will be deserialized into a `Dynamic` object
-/// and passed to the expression evaluation.
+/// The context is a JSON string deserialized into a `Dynamic` and passed to the
+/// evaluation. `limits`/`capabilities` as in `compile_ast`. Malformed input and
+/// native-function panics are reported as `ResultCString::Err` rather than
+/// unwinding across the FFI boundary.
 #[no_mangle]
 pub extern "C" fn perform_eval(
     expression: *const c_char,
     expression_len: usize,
     context_json: *const c_char,
     context_len: usize,
+    limits: *const ResourceLimits,
+    capabilities: u32,
 ) -> ResultCString {
     if expression.is_null() || expression_len == 0 {
         return ResultCString::Ok(ptr::null_mut());
     }
 
-    let expr_str = make_str!(expression, expression_len);
+    let expr_str = match unsafe { bytes_to_str(expression, expression_len) } {
+        Ok(expr_str) => expr_str,
+        Err(error) => {
+            return result_cstring_err(format!("expression was not valid UTF-8: {}", error))
+        }
+    };
 
     let mut engine = Engine::new();
 
-    engine.register_global_module(RandomPackage::new().as_shared_module());
-    engine.register_global_module(FilesystemPackage::new().as_shared_module());
-    engine.register_global_module(UrlPackage::new().as_shared_module());
+    configure_capabilities(&mut engine, capabilities);
     //    engine.register_global_module(ChronoPackage::new().as_shared_module());
+    apply_resource_limits(&mut engine, limits);
+    install_timeout(&mut engine, resource_timeout_ms(limits));
 
     // The json context is optional.
-    let result = match context_len == 0 {
-        false => {
-            let context_str = make_str!(context_json, context_len);
+    let context = if context_len != 0 {
+        let context_str = match unsafe { bytes_to_str(context_json, context_len) } {
+            Ok(context_str) => context_str,
+            Err(error) => {
+                return result_cstring_err(format!("context JSON was not valid UTF-8: {}", error))
+            }
+        };
 
-            // Deserialize 'Dynamic' from JSON
-            let context: Dynamic = serde_json::from_str(&context_str).expect(
-                format!("JSON context was not well formed, length {}", context_len).as_str(),
-            );
+        if let Err(error) = reject_oversized_integer_literals(context_str) {
+            return result_cstring_err(format!("JSON context was not well formed: {}", error));
+        }
 
-            // First create the state
-            let mut scope = Scope::new();
+        match serde_json::from_str::<serde_json::Value>(context_str) {
+            Ok(context) => match json_value_to_dynamic(context) {
+                Ok(context) => Some(context),
+                Err(error) => {
+                    return result_cstring_err(format!(
+                        "JSON context was not well formed, length {}: {}",
+                        context_len, error
+                    ))
+                }
+            },
+            Err(error) => {
+                return result_cstring_err(format!(
+                    "JSON context was not well formed, length {}: {}",
+                    context_len, error
+                ))
+            }
+        }
+    } else {
+        None
+    };
 
+    let eval_result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || match context {
+        Some(context) => {
+            let mut scope = Scope::new();
             scope.push("context", context);
-
-            // Use the context in an expression
             engine.eval_with_scope::<Dynamic>(&mut scope, expr_str)
         }
-        true => engine.eval::<Dynamic>(expr_str),
+        None => engine.eval::<Dynamic>(expr_str),
+    }));
+
+    let result = match eval_result {
+        Ok(result) => result,
+        Err(_) => return result_cstring_err("evaluation panicked".to_string()),
     };
 
     match result {
-        Ok(output) => {
-            let json = serde_json::to_string(&output).expect("Failed to serialize result to JSON");
-            let value_str = CString::new(json).unwrap();
-            ResultCString::Ok(value_str.into_raw())
-        }
-        Err(error) => {
-            let formatted_error = format!("{}", error);
-            let error_str = CString::new(formatted_error).unwrap();
-            ResultCString::Err(error_str.into_raw())
-        }
+        Ok(output) => match serde_json::to_string(&dynamic_to_json_value(&output)) {
+            Ok(json) => result_cstring_ok(json),
+            Err(error) => {
+                result_cstring_err(format!("failed to serialize result to JSON: {}", error))
+            }
+        },
+        Err(error) => result_cstring_err(format_eval_error(&error)),
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
 
+    fn as_bytes(s: &str) -> (*const c_char, usize) {
+        (s.as_ptr() as *const c_char, s.len())
+    }
 
+    fn compile_ok(expr: &str) -> *mut CompiledAst {
+        let (expr_ptr, expr_len) = as_bytes(expr);
+        let result = unsafe { Box::from_raw(compile_ast(expr_ptr, expr_len, ptr::null(), 0)) };
+        match *result {
+            ResultCompiledAst::Ok(compiled) => compiled,
+            ResultCompiledAst::Err(_) => panic!("expected '{}' to compile", expr),
+        }
+    }
+
+    fn result_cstring_text(result: ResultCString) -> (bool, String) {
+        let (is_err, message) = match result {
+            ResultCString::Ok(message) => (false, message),
+            ResultCString::Err(message) => (true, message),
+        };
+        let text = unsafe { CString::from_raw(message) }
+            .to_string_lossy()
+            .into_owned();
+        (is_err, text)
+    }
+
+    #[test]
+    fn malformed_utf8_expression_returns_err_not_panic() {
+        let bytes: &[u8] = &[0xff, 0xfe, 0xfd];
+        let result = unsafe {
+            Box::from_raw(compile_ast(
+                bytes.as_ptr() as *const c_char,
+                bytes.len(),
+                ptr::null(),
+                0,
+            ))
+        };
+        assert!(matches!(*result, ResultCompiledAst::Err(_)));
+    }
+
+    #[test]
+    fn malformed_json_context_returns_err_not_panic() {
+        let compiled = compile_ok("context");
+        let (ctx_ptr, ctx_len) = as_bytes("{not valid json");
+
+        let result = eval_ast(compiled, ctx_ptr, ctx_len);
+        assert!(matches!(result, ResultCString::Err(_)));
+
+        free_ast(compiled);
+    }
+
+    #[test]
+    fn huge_integer_round_trips_through_json_string() {
+        let value = json_value_to_dynamic(serde_json::Value::String(i64::MAX.to_string()))
+            .expect("i64::MAX fits Rhai's INT");
+        let json = dynamic_to_json_value(&value);
+        assert_eq!(json, serde_json::Value::String(i64::MAX.to_string()));
+    }
+
+    #[test]
+    fn integer_beyond_i64_range_is_rejected_not_corrupted() {
+        let huge = "170141183460469231731687303715884105727"; // i128::MAX
+        assert!(json_value_to_dynamic(serde_json::Value::String(huge.to_string())).is_err());
+    }
+
+    #[test]
+    fn default_capability_profile_disables_eval() {
+        let (expr_ptr, expr_len) = as_bytes("eval(\"1 + 1\")");
+        let result = unsafe { Box::from_raw(compile_ast(expr_ptr, expr_len, ptr::null(), 0)) };
+        assert!(matches!(*result, ResultCompiledAst::Err(_)));
+    }
+
+    #[test]
+    fn timeout_error_message_includes_diagnostic_token() {
+        let limits = ResourceLimits {
+            max_operations: 0,
+            max_expr_depth: 0,
+            max_array_size: 0,
+            max_string_size: 0,
+            max_map_size: 0,
+            timeout_ms: 1,
+        };
+        let (expr_ptr, expr_len) = as_bytes("let x = 0; while true { x += 1; }");
+        let result = unsafe { Box::from_raw(compile_ast(expr_ptr, expr_len, &limits, 0)) };
+        let compiled = match *result {
+            ResultCompiledAst::Ok(compiled) => compiled,
+            ResultCompiledAst::Err(_) => panic!("expected busy loop to compile"),
+        };
+
+        let (is_err, message) = result_cstring_text(eval_ast(compiled, ptr::null(), 0));
+        assert!(is_err);
+        assert!(message.contains("ms time limit"), "got: {}", message);
+
+        free_ast(compiled);
+    }
+
+    #[test]
+    fn unquoted_huge_integer_in_context_is_rejected() {
+        let compiled = compile_ok("context");
+        let (ctx_ptr, ctx_len) = as_bytes("99999999999999999999999999999999999999");
+
+        let (is_err, message) = result_cstring_text(eval_ast(compiled, ctx_ptr, ctx_len));
+        assert!(is_err);
+        assert!(message.contains("quoted"), "got: {}", message);
+
+        free_ast(compiled);
+    }
+
+    #[test]
+    fn stateful_eval_persists_state_and_reset_clears_it() {
+        let compiled = compile_ok(
+            "if type_of(state()) == \"()\" { set_state(1); } else { set_state(state() + 1); } state()",
+        );
+
+        let (first_is_err, first) = result_cstring_text(eval_ast_stateful(compiled, ptr::null(), 0));
+        assert!(!first_is_err);
+        assert_eq!(first, "1");
+
+        let (second_is_err, second) =
+            result_cstring_text(eval_ast_stateful(compiled, ptr::null(), 0));
+        assert!(!second_is_err);
+        assert_eq!(second, "2");
+
+        reset_ast_state(compiled);
+
+        let (third_is_err, third) = result_cstring_text(eval_ast_stateful(compiled, ptr::null(), 0));
+        assert!(!third_is_err);
+        assert_eq!(third, "1");
+
+        free_ast(compiled);
+    }
+
+    #[test]
+    fn call_ast_fn_invokes_named_function_and_round_trips_result() {
+        let compiled = compile_ok("fn add(a, b) { a + b }");
+        let (fn_name_ptr, fn_name_len) = as_bytes("add");
+        let (args_ptr, args_len) = as_bytes("[2, 3]");
+
+        let (is_err, result) = result_cstring_text(call_ast_fn(
+            compiled,
+            fn_name_ptr,
+            fn_name_len,
+            args_ptr,
+            args_len,
+        ));
+        assert!(!is_err);
+        assert_eq!(result, "5");
+
+        free_ast(compiled);
+    }
+}